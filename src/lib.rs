@@ -1,8 +1,10 @@
-use std::sync::LazyLock;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hasher;
+use std::sync::{LazyLock, Mutex};
 
 use minijinja::{Environment, context};
 use minijinja_contrib::pycompat;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokenizers::{InputSequence, Tokenizer};
 
 #[derive(Debug, thiserror::Error)]
@@ -13,25 +15,141 @@ pub enum Error {
     ThreadPool(#[from] async_threadpool::Error),
     #[error("Failed to render chat template: {0}")]
     Template(#[from] minijinja::Error),
+    #[error("Failed to serialize chat input for cache key: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("Unknown model: {0}")]
+    UnknownModel(String),
+    #[error("Model {0} has no chat template")]
+    NoChatTemplate(String),
 }
 
-static GLM_TOKENIZER: LazyLock<Tokenizer> =
-    LazyLock::new(|| Tokenizer::from_bytes(include_bytes!("../glm.json")).unwrap());
+static GLM_TOKENIZER_BYTES: &[u8] = include_bytes!("../glm.json");
 
 static GLM_CHAT_TEMPLATE: &str = include_str!("../glm_4_6_chat_template.jinja");
 
+/// Special tokens configured for the bundled GLM tokenizer. These mirror the
+/// `bos_token`/`eos_token`/`additional_special_tokens` entries in GLM's
+/// `tokenizer_config.json` and are threaded into the chat template context so
+/// templates that reference them render faithfully.
+static GLM_BOS_TOKEN: &str = "[gMASK]<sop>";
+static GLM_EOS_TOKEN: &str = "<|endoftext|>";
+static GLM_ADDITIONAL_SPECIAL_TOKENS: &[&str] =
+    &["<|user|>", "<|assistant|>", "<|system|>", "<|observation|>"];
+
+/// Name the bundled GLM model is preregistered under in the default registry.
+const GLM_MODEL: &str = "glm";
+
+/// `raise_exception` is a function chat templates call to reject malformed role
+/// sequences. We surface its message as a [`minijinja::Error`], which bubbles up
+/// through [`Error::Template`].
+fn raise_exception(msg: String) -> Result<String, minijinja::Error> {
+    Err(minijinja::Error::new(
+        minijinja::ErrorKind::InvalidOperation,
+        msg,
+    ))
+}
+
+/// Build a minijinja [`Environment`] preloaded with a chat `template`, the
+/// pycompat method shim, and the `raise_exception` global.
+fn chat_environment(template: String) -> Result<Environment<'static>, minijinja::Error> {
+    let mut env = Environment::new();
+    // add support for jinja/python methods like strip
+    env.set_unknown_method_callback(pycompat::unknown_method_callback);
+    env.add_function("raise_exception", raise_exception);
+    env.add_template_owned("chat", template)?;
+    Ok(env)
+}
+
+/// A tool/function call emitted by an assistant turn.
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolCall {
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+/// Number of tokens a single image contributes under GLM's vision encoder
+/// (the fixed per-image tile count). Added to the text token count so the total
+/// returned by [`glm_chat`] reflects what the server will actually bill.
+pub const GLM_IMAGE_TOKENS: usize = 1600;
+
+/// The location of an image referenced by a [`ContentPart`], either a data URL
+/// or a local path.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageUrl {
+    pub url: String,
+}
+
+/// A typed piece of a multimodal message's content.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    Image { image_url: ImageUrl },
+}
+
+impl ContentPart {
+    pub fn text(text: String) -> Self {
+        Self::Text { text }
+    }
+
+    pub fn image(url: String) -> Self {
+        Self::Image {
+            image_url: ImageUrl { url },
+        }
+    }
+}
+
+/// A message's content: either plain text or a list of typed parts.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl From<String> for MessageContent {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<Vec<ContentPart>> for MessageContent {
+    fn from(parts: Vec<ContentPart>) -> Self {
+        Self::Parts(parts)
+    }
+}
+
 /// A message for tokenization with chat template applied
 #[derive(Debug, Clone, Serialize)]
 pub struct ChatMessage {
     pub role: String,
-    pub content: String,
+    pub content: MessageContent,
+    /// Tool calls requested by an assistant turn, if any.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// Identifier of the call a `tool` message is responding to, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 
 impl ChatMessage {
-    fn new(role: &'static str, content: String) -> Self {
+    fn new(role: &'static str, content: impl Into<MessageContent>) -> Self {
         Self {
             role: role.into(),
-            content,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    /// Number of image parts carried by this message.
+    fn image_count(&self) -> usize {
+        match &self.content {
+            MessageContent::Text(_) => 0,
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter(|part| matches!(part, ContentPart::Image { .. }))
+                .count(),
         }
     }
 
@@ -43,6 +161,11 @@ impl ChatMessage {
         Self::new("user", content)
     }
 
+    /// A user turn carrying multimodal content parts (text and/or images).
+    pub fn user_parts(parts: Vec<ContentPart>) -> Self {
+        Self::new("user", parts)
+    }
+
     pub fn assistant(content: String) -> Self {
         Self::new("assistant", content)
     }
@@ -50,41 +173,503 @@ impl ChatMessage {
     pub fn tool(content: String) -> Self {
         Self::new("tool", content)
     }
+
+    /// Attach tool calls to an assistant turn.
+    pub fn with_tool_calls(mut self, tool_calls: Vec<ToolCall>) -> Self {
+        self.tool_calls = tool_calls;
+        self
+    }
+
+    /// Attach the id of the tool call this message responds to.
+    pub fn with_tool_call_id(mut self, tool_call_id: String) -> Self {
+        self.tool_call_id = Some(tool_call_id);
+        self
+    }
+}
+
+/// The result of tokenizing an input: the token IDs, their token strings as
+/// they appear in the tokenizer's vocabulary, and the byte offset span each
+/// token covers in the source text.
+///
+/// Returned by [`glm_encode`]/[`glm_chat_encode`] for callers building
+/// truncation, highlighting, or streaming-boundary logic that needs more than a
+/// count. For chat inputs the offsets refer to the rendered template text;
+/// image parts have no rendered token span of their own, so their fixed
+/// contribution is carried in [`image_tokens`](Self::image_tokens) instead of
+/// in `ids`/`offsets`. Use [`total_len`](Self::total_len) for the value that
+/// matches [`glm_chat`]'s count.
+#[derive(Debug, Clone)]
+pub struct Encoding {
+    pub ids: Vec<u32>,
+    pub tokens: Vec<String>,
+    pub offsets: Vec<(usize, usize)>,
+    /// Fixed token contribution of any image parts, not represented in `ids`.
+    pub image_tokens: usize,
+}
+
+impl Encoding {
+    /// Number of text tokens, i.e. entries in `ids`/`tokens`/`offsets`. This
+    /// excludes [`image_tokens`](Self::image_tokens); see [`total_len`](Self::total_len).
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Total token count including the image contribution — the value that
+    /// agrees with [`glm_chat`].
+    pub fn total_len(&self) -> usize {
+        self.ids.len() + self.image_tokens
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty() && self.image_tokens == 0
+    }
+}
+
+impl From<tokenizers::Encoding> for Encoding {
+    fn from(encoding: tokenizers::Encoding) -> Self {
+        Self {
+            ids: encoding.get_ids().to_vec(),
+            tokens: encoding.get_tokens().to_vec(),
+            offsets: encoding.get_offsets().to_vec(),
+            image_tokens: 0,
+        }
+    }
+}
+
+/// A special token as it appears in a `tokenizer_config.json`: either a bare
+/// string or an `AddedToken` object with a `content` field.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum SpecialToken {
+    Str(String),
+    Obj { content: String },
+}
+
+impl SpecialToken {
+    fn into_content(self) -> String {
+        match self {
+            SpecialToken::Str(content) => content,
+            SpecialToken::Obj { content } => content,
+        }
+    }
+}
+
+/// The subset of Hugging Face `tokenizer_config.json` we consume.
+#[derive(Debug, Default, Deserialize)]
+struct TokenizerConfig {
+    chat_template: Option<String>,
+    bos_token: Option<SpecialToken>,
+    eos_token: Option<SpecialToken>,
+    #[serde(default)]
+    additional_special_tokens: Vec<SpecialToken>,
+}
+
+/// A tokenizer plus the chat-template machinery derived from its config.
+struct RegisteredModel {
+    tokenizer: Tokenizer,
+    chat_env: Option<Environment<'static>>,
+    bos_token: Option<String>,
+    eos_token: Option<String>,
+    additional_special_tokens: Vec<String>,
+    /// Fixed token contribution of a single image part for this model. `0`
+    /// means image accounting is disabled (the default for models registered
+    /// at runtime, whose vision tile count we don't know).
+    image_tokens: usize,
+}
+
+impl RegisteredModel {
+    /// Total fixed image-token contribution across `messages` for this model.
+    fn image_tokens(&self, messages: &[ChatMessage]) -> usize {
+        messages.iter().map(ChatMessage::image_count).sum::<usize>() * self.image_tokens
+    }
+
+    /// Render this model's chat template against the given messages and tools.
+    fn render_chat(
+        &self,
+        name: &str,
+        messages: &[ChatMessage],
+        tools: &[serde_json::Value],
+        add_generation_prompt: bool,
+    ) -> Result<String, Error> {
+        let env = self
+            .chat_env
+            .as_ref()
+            .ok_or_else(|| Error::NoChatTemplate(name.to_owned()))?;
+        let tmpl = env.get_template("chat")?;
+        Ok(tmpl.render(context! {
+            messages => messages,
+            tools => tools,
+            add_generation_prompt => add_generation_prompt,
+            bos_token => self.bos_token,
+            eos_token => self.eos_token,
+            additional_special_tokens => self.additional_special_tokens,
+        })?)
+    }
+}
+
+/// A registry of chat-template-aware tokenizers keyed by model name.
+///
+/// Register additional models at runtime from a `tokenizer.json` plus its
+/// `tokenizer_config.json` (which supplies the `chat_template`, `bos_token`,
+/// `eos_token`, and special tokens), then tokenize through [`encode`] and
+/// [`encode_chat`]. The bundled GLM model is preregistered in
+/// [`default_registry`], and the free [`glm`]/[`glm_chat`] functions are thin
+/// wrappers around it.
+///
+/// [`encode`]: TokenizerRegistry::encode
+/// [`encode_chat`]: TokenizerRegistry::encode_chat
+#[derive(Default)]
+pub struct TokenizerRegistry {
+    models: HashMap<String, RegisteredModel>,
+}
+
+impl TokenizerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a model from its `tokenizer.json` and `tokenizer_config.json`
+    /// bytes, replacing any model already registered under `model`.
+    ///
+    /// Image accounting is disabled for models registered this way — a
+    /// `tokenizer_config.json` carries no vision tile count, and billing GLM's
+    /// per-image cost to an arbitrary model would silently mis-count. Use
+    /// [`register_with_image_tokens`](Self::register_with_image_tokens) when the
+    /// model's fixed per-image contribution is known.
+    pub fn register(
+        &mut self,
+        model: impl Into<String>,
+        tokenizer_json: &[u8],
+        tokenizer_config_json: &[u8],
+    ) -> Result<(), Error> {
+        self.register_with_image_tokens(model, tokenizer_json, tokenizer_config_json, 0)
+    }
+
+    /// Register a model as [`register`](Self::register) does, but with a known
+    /// fixed per-image token contribution used by [`encode_chat`](Self::encode_chat)
+    /// for image content parts.
+    pub fn register_with_image_tokens(
+        &mut self,
+        model: impl Into<String>,
+        tokenizer_json: &[u8],
+        tokenizer_config_json: &[u8],
+        image_tokens: usize,
+    ) -> Result<(), Error> {
+        let tokenizer = Tokenizer::from_bytes(tokenizer_json)?;
+        let config: TokenizerConfig = serde_json::from_slice(tokenizer_config_json)?;
+        let chat_env = config.chat_template.map(chat_environment).transpose()?;
+
+        self.models.insert(
+            model.into(),
+            RegisteredModel {
+                tokenizer,
+                chat_env,
+                bos_token: config.bos_token.map(SpecialToken::into_content),
+                eos_token: config.eos_token.map(SpecialToken::into_content),
+                additional_special_tokens: config
+                    .additional_special_tokens
+                    .into_iter()
+                    .map(SpecialToken::into_content)
+                    .collect(),
+                image_tokens,
+            },
+        );
+        Ok(())
+    }
+
+    fn model(&self, model: &str) -> Result<&RegisteredModel, Error> {
+        self.models
+            .get(model)
+            .ok_or_else(|| Error::UnknownModel(model.to_owned()))
+    }
+
+    /// Tokenize raw text with `model` (without chat template).
+    pub fn encode<'a, E: Into<InputSequence<'a>>>(
+        &self,
+        model: &str,
+        input: E,
+    ) -> Result<usize, Error> {
+        Ok(self.model(model)?.tokenizer.encode(input.into(), false)?.len())
+    }
+
+    /// Tokenize messages with `model`'s chat template.
+    pub fn encode_chat(
+        &self,
+        model: &str,
+        messages: &[ChatMessage],
+        tools: &[serde_json::Value],
+        add_generation_prompt: bool,
+    ) -> Result<usize, Error> {
+        let registered = self.model(model)?;
+        let image_tokens = registered.image_tokens(messages);
+        let formatted = registered.render_chat(model, messages, tools, add_generation_prompt)?;
+        Ok(registered.tokenizer.encode(formatted, false)?.len() + image_tokens)
+    }
+}
+
+/// The process-wide registry, preloaded with the bundled GLM model.
+static DEFAULT_REGISTRY: LazyLock<TokenizerRegistry> = LazyLock::new(|| {
+    let mut registry = TokenizerRegistry::new();
+    let tokenizer = Tokenizer::from_bytes(GLM_TOKENIZER_BYTES).expect("GLM tokenizer should load");
+    let chat_env =
+        chat_environment(GLM_CHAT_TEMPLATE.to_owned()).expect("GLM chat template should parse");
+    registry.models.insert(
+        GLM_MODEL.to_owned(),
+        RegisteredModel {
+            tokenizer,
+            chat_env: Some(chat_env),
+            bos_token: Some(GLM_BOS_TOKEN.to_owned()),
+            eos_token: Some(GLM_EOS_TOKEN.to_owned()),
+            additional_special_tokens: GLM_ADDITIONAL_SPECIAL_TOKENS
+                .iter()
+                .map(|token| (*token).to_owned())
+                .collect(),
+            image_tokens: GLM_IMAGE_TOKENS,
+        },
+    );
+    registry
+});
+
+/// The process-wide [`TokenizerRegistry`] with the bundled GLM model
+/// preregistered under the name `"glm"`.
+pub fn default_registry() -> &'static TokenizerRegistry {
+    &DEFAULT_REGISTRY
+}
+
+/// Render the GLM chat template and tokenize it, returning the raw encoding
+/// alongside the fixed token contribution of any image parts.
+fn glm_chat_encode_sync(
+    messages: &[ChatMessage],
+    tools: &[serde_json::Value],
+    add_generation_prompt: bool,
+) -> Result<(tokenizers::Encoding, usize), Error> {
+    let model = DEFAULT_REGISTRY.model(GLM_MODEL)?;
+    let image_tokens = model.image_tokens(messages);
+    let formatted = model.render_chat(GLM_MODEL, messages, tools, add_generation_prompt)?;
+
+    Ok((model.tokenizer.encode(formatted, false)?, image_tokens))
+}
+
+/// Tokenize raw text per GLM (without chat template), returning the full
+/// [`Encoding`].
+pub async fn glm_encode<'a, E: Into<InputSequence<'a>> + Send + 'static>(
+    input: E,
+) -> Result<Encoding, Error> {
+    async_threadpool::run(|| {
+        Ok(DEFAULT_REGISTRY
+            .model(GLM_MODEL)?
+            .tokenizer
+            .encode(input.into(), false)?
+            .into())
+    })
+    .await?
 }
 
 /// Tokenize raw text per GLM (without chat template)
 pub async fn glm<'a, E: Into<InputSequence<'a>> + Send + 'static>(
     input: E,
 ) -> Result<usize, Error> {
-    async_threadpool::run(|| Ok(GLM_TOKENIZER.encode(input.into(), false)?.len())).await?
+    Ok(glm_encode(input).await?.len())
 }
 
-/// Tokenize messages (with GLM chat template)
-pub async fn glm_chat(messages: Vec<ChatMessage>) -> Result<usize, Error> {
+/// Tokenize many raw inputs per GLM in a single pool task.
+///
+/// Uses the tokenizer's native `encode_batch`, so the thread-hop is amortized
+/// across the whole batch and the tokenizer parallelizes internally — a large
+/// throughput win over calling [`glm`] in a loop when scoring many candidates.
+pub async fn glm_batch<'a, E: Into<InputSequence<'a>> + Send + 'static>(
+    inputs: Vec<E>,
+) -> Result<Vec<usize>, Error> {
     async_threadpool::run(move || {
-        let mut env = Environment::new();
-        // add support for jinja/python methods like strip
-        env.set_unknown_method_callback(pycompat::unknown_method_callback);
-        env.add_template("chat", GLM_CHAT_TEMPLATE)?;
-        let tmpl = env.get_template("chat")?;
+        let encodings = DEFAULT_REGISTRY
+            .model(GLM_MODEL)?
+            .tokenizer
+            .encode_batch(inputs, false)?;
+        Ok(encodings.iter().map(tokenizers::Encoding::len).collect())
+    })
+    .await?
+}
 
-        let formatted = tmpl.render(context! {
-            messages => messages,
-            add_generation_prompt => true,
-        })?;
+/// Tokenize messages (with GLM chat template), returning the full [`Encoding`]
+/// of the rendered template text.
+///
+/// The `ids`/`tokens`/`offsets` cover the rendered text; the fixed per-image
+/// contribution is carried in [`Encoding::image_tokens`], so
+/// [`Encoding::total_len`] agrees with [`glm_chat`]'s count.
+///
+/// When `add_generation_prompt` is `true` the template appends the assistant
+/// generation prefix; pass `false` to tokenize a finalized assistant turn
+/// (e.g. for training or eval).
+pub async fn glm_chat_encode(
+    messages: Vec<ChatMessage>,
+    tools: Vec<serde_json::Value>,
+    add_generation_prompt: bool,
+) -> Result<Encoding, Error> {
+    async_threadpool::run(move || {
+        let (encoding, image_tokens) =
+            glm_chat_encode_sync(&messages, &tools, add_generation_prompt)?;
+        let mut encoding: Encoding = encoding.into();
+        encoding.image_tokens = image_tokens;
+        Ok(encoding)
+    })
+    .await?
+}
+
+/// Tokenize messages (with GLM chat template).
+///
+/// When `add_generation_prompt` is `true` the template appends the assistant
+/// generation prefix; pass `false` to tokenize a finalized assistant turn
+/// (e.g. for training or eval).
+pub async fn glm_chat(
+    messages: Vec<ChatMessage>,
+    tools: Vec<serde_json::Value>,
+    add_generation_prompt: bool,
+) -> Result<usize, Error> {
+    async_threadpool::run(move || {
+        let (encoding, image_tokens) =
+            glm_chat_encode_sync(&messages, &tools, add_generation_prompt)?;
+        Ok(encoding.len() + image_tokens)
+    })
+    .await?
+}
+
+/// Tokenize many conversations (with GLM chat template) in a single pool task.
+///
+/// Each conversation is rendered with the shared `tools`/`add_generation_prompt`
+/// settings, then all rendered prompts are tokenized through the native
+/// `encode_batch`. Returns one count per conversation, including per-image
+/// tokens, in input order.
+pub async fn glm_chat_batch(
+    conversations: Vec<Vec<ChatMessage>>,
+    tools: Vec<serde_json::Value>,
+    add_generation_prompt: bool,
+) -> Result<Vec<usize>, Error> {
+    async_threadpool::run(move || {
+        let model = DEFAULT_REGISTRY.model(GLM_MODEL)?;
+
+        let mut rendered = Vec::with_capacity(conversations.len());
+        let mut image_tokens = Vec::with_capacity(conversations.len());
+        for messages in &conversations {
+            rendered.push(model.render_chat(GLM_MODEL, messages, &tools, add_generation_prompt)?);
+            image_tokens.push(model.image_tokens(messages));
+        }
 
-        Ok(GLM_TOKENIZER.encode(formatted, false)?.len())
+        let encodings = model.tokenizer.encode_batch(rendered, false)?;
+        Ok(encodings
+            .iter()
+            .zip(image_tokens)
+            .map(|(encoding, images)| encoding.len() + images)
+            .collect())
     })
     .await?
 }
 
+/// Default maximum number of entries retained by the chat render cache.
+pub const DEFAULT_CHAT_CACHE_CAPACITY: usize = 1024;
+
+/// A bounded map from conversation hash to token count with FIFO eviction.
+struct BoundedCache {
+    capacity: usize,
+    map: HashMap<u64, usize>,
+    order: VecDeque<u64>,
+}
+
+impl BoundedCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            map: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get(&self, key: u64) -> Option<usize> {
+        self.map.get(&key).copied()
+    }
+
+    fn insert(&mut self, key: u64, value: usize) {
+        if self.capacity == 0 || self.map.contains_key(&key) {
+            if self.capacity != 0 {
+                self.map.insert(key, value);
+            }
+            return;
+        }
+        while self.map.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some(evicted) => {
+                    self.map.remove(&evicted);
+                }
+                None => break,
+            }
+        }
+        self.map.insert(key, value);
+        self.order.push_back(key);
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.map.len() > self.capacity {
+            match self.order.pop_front() {
+                Some(evicted) => {
+                    self.map.remove(&evicted);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+static CHAT_CACHE: LazyLock<Mutex<BoundedCache>> =
+    LazyLock::new(|| Mutex::new(BoundedCache::new(DEFAULT_CHAT_CACHE_CAPACITY)));
+
+/// Set the maximum number of entries the chat render cache retains, evicting
+/// the oldest entries if the new capacity is smaller than the current size.
+pub fn set_chat_cache_capacity(capacity: usize) {
+    CHAT_CACHE.lock().unwrap().set_capacity(capacity);
+}
+
+/// Hash the render inputs with xxhash to key the cache.
+fn chat_cache_key(
+    messages: &[ChatMessage],
+    tools: &[serde_json::Value],
+    add_generation_prompt: bool,
+) -> Result<u64, Error> {
+    let mut hasher = twox_hash::XxHash64::with_seed(0);
+    hasher.write(&serde_json::to_vec(messages)?);
+    hasher.write(&serde_json::to_vec(tools)?);
+    hasher.write(&[add_generation_prompt as u8]);
+    Ok(hasher.finish())
+}
+
+/// Tokenize messages (with GLM chat template), consulting a bounded cache keyed
+/// by a hash of `messages`/`tools`/`add_generation_prompt`.
+///
+/// Caching is opt-in via this function: [`glm_chat`] never touches shared state
+/// and stays fully deterministic. Repeated tokenization of identical prefixes
+/// (e.g. a UI re-measuring on each keystroke) returns the stored count without
+/// re-rendering or re-encoding. Tune the bound with [`set_chat_cache_capacity`].
+pub async fn glm_chat_cached(
+    messages: Vec<ChatMessage>,
+    tools: Vec<serde_json::Value>,
+    add_generation_prompt: bool,
+) -> Result<usize, Error> {
+    let key = chat_cache_key(&messages, &tools, add_generation_prompt)?;
+    if let Some(count) = CHAT_CACHE.lock().unwrap().get(key) {
+        return Ok(count);
+    }
+
+    let count = glm_chat(messages, tools, add_generation_prompt).await?;
+    CHAT_CACHE.lock().unwrap().insert(key, count);
+    Ok(count)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn initialization() {
-        LazyLock::force(&GLM_TOKENIZER);
+        LazyLock::force(&DEFAULT_REGISTRY);
     }
 
     #[tokio::test]
@@ -97,7 +682,7 @@ mod tests {
     #[tokio::test]
     async fn test_glm_chat() {
         let messages = vec![ChatMessage::user("Hello, world!".to_owned())];
-        let result = glm_chat(messages).await.unwrap();
+        let result = glm_chat(messages, vec![], true).await.unwrap();
         // Should be more than raw tokenization due to template tokens
         assert!(result > 4);
     }
@@ -106,7 +691,7 @@ mod tests {
     async fn test_glm_chat_vs_raw() {
         let content = "Hello, world!";
         let raw_tokens = glm(content).await.unwrap();
-        let chat_tokens = glm_chat(vec![ChatMessage::user(content.into())])
+        let chat_tokens = glm_chat(vec![ChatMessage::user(content.into())], vec![], true)
             .await
             .unwrap();
 
@@ -120,7 +705,287 @@ mod tests {
             ChatMessage::user("Hello!".to_owned()),
             ChatMessage::assistant("Hi there!".to_owned()),
         ];
-        let result = glm_chat(messages).await.unwrap();
+        let result = glm_chat(messages, vec![], true).await.unwrap();
         assert!(result > 0);
     }
+
+    #[tokio::test]
+    async fn test_generation_prompt_adds_tokens() {
+        let messages = vec![ChatMessage::user("Hello!".to_owned())];
+        let with_prompt = glm_chat(messages.clone(), vec![], true).await.unwrap();
+        let without_prompt = glm_chat(messages, vec![], false).await.unwrap();
+        assert!(with_prompt > without_prompt);
+    }
+
+    #[tokio::test]
+    async fn test_glm_batch_matches_singles() {
+        let inputs = vec!["Hello, world!", "Another input", "三番目"];
+        let batch = glm_batch(inputs.clone()).await.unwrap();
+
+        assert_eq!(batch.len(), inputs.len());
+        for (input, count) in inputs.into_iter().zip(&batch) {
+            assert_eq!(glm(input).await.unwrap(), *count);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_glm_chat_batch_matches_singles() {
+        let conversations = vec![
+            vec![ChatMessage::user("First".to_owned())],
+            vec![
+                ChatMessage::user("Second".to_owned()),
+                ChatMessage::assistant("reply".to_owned()),
+            ],
+        ];
+        let batch = glm_chat_batch(conversations.clone(), vec![], true)
+            .await
+            .unwrap();
+
+        assert_eq!(batch.len(), conversations.len());
+        for (messages, count) in conversations.into_iter().zip(&batch) {
+            assert_eq!(glm_chat(messages, vec![], true).await.unwrap(), *count);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registry_default_matches_free_functions() {
+        let registry = default_registry();
+        let messages = vec![ChatMessage::user("Hello, world!".to_owned())];
+
+        assert_eq!(registry.encode(GLM_MODEL, "Hello, world!").unwrap(), 4);
+        assert_eq!(
+            registry.encode(GLM_MODEL, "Hello, world!").unwrap(),
+            glm("Hello, world!").await.unwrap()
+        );
+        let via_registry = registry.encode_chat(GLM_MODEL, &messages, &[], true).unwrap();
+        assert!(via_registry > 4);
+        assert_eq!(via_registry, glm_chat(messages, vec![], true).await.unwrap());
+    }
+
+    #[test]
+    fn test_registry_register_from_config() {
+        let config = br#"{
+            "chat_template": "{%- for m in messages -%}<|{{ m.role }}|>{{ m.content }}{%- endfor -%}{{ bos_token }}",
+            "bos_token": "<s>",
+            "eos_token": { "content": "</s>" },
+            "additional_special_tokens": ["<|tool|>"]
+        }"#;
+
+        let mut registry = TokenizerRegistry::new();
+        registry
+            .register("glm-copy", GLM_TOKENIZER_BYTES, config)
+            .unwrap();
+
+        let messages = vec![ChatMessage::user("hi".to_owned())];
+        let count = registry
+            .encode_chat("glm-copy", &messages, &[], false)
+            .unwrap();
+        assert!(count > 0);
+
+        // Unknown models surface a typed error.
+        let err = registry.encode("absent", "hi").unwrap_err();
+        assert!(matches!(err, Error::UnknownModel(_)));
+    }
+
+    #[test]
+    fn test_registry_omits_image_tokens_for_runtime_models() {
+        // A template that ignores content, so a text part and an image part
+        // render identical text. Any difference in the count would come solely
+        // from image accounting — which must not happen for a runtime-registered
+        // model, since GLM's tile count doesn't apply to it.
+        let config = br#"{
+            "chat_template": "{%- for m in messages -%}<|{{ m.role }}|>{%- endfor -%}"
+        }"#;
+
+        let mut registry = TokenizerRegistry::new();
+        registry.register("other", GLM_TOKENIZER_BYTES, config).unwrap();
+
+        let text = vec![ChatMessage::user_parts(vec![ContentPart::text("hi".to_owned())])];
+        let image = vec![ChatMessage::user_parts(vec![ContentPart::image(
+            "https://example.com/cat.png".to_owned(),
+        )])];
+
+        let text_count = registry.encode_chat("other", &text, &[], false).unwrap();
+        let image_count = registry.encode_chat("other", &image, &[], false).unwrap();
+        assert_eq!(text_count, image_count);
+
+        // Opting in restores per-image accounting for a known tile count.
+        registry
+            .register_with_image_tokens("other-vision", GLM_TOKENIZER_BYTES, config, 256)
+            .unwrap();
+        let vision_count = registry.encode_chat("other-vision", &image, &[], false).unwrap();
+        assert_eq!(vision_count, image_count + 256);
+    }
+
+    #[tokio::test]
+    async fn test_glm_chat_cached_matches_uncached() {
+        let messages = vec![ChatMessage::user("Cache me if you can".to_owned())];
+        let uncached = glm_chat(messages.clone(), vec![], true).await.unwrap();
+        // First call populates the cache, second hits it; both agree with glm_chat.
+        let first = glm_chat_cached(messages.clone(), vec![], true).await.unwrap();
+        let second = glm_chat_cached(messages, vec![], true).await.unwrap();
+        assert_eq!(first, uncached);
+        assert_eq!(second, uncached);
+    }
+
+    #[test]
+    fn test_bounded_cache_evicts_oldest() {
+        let mut cache = BoundedCache::new(2);
+        cache.insert(1, 10);
+        cache.insert(2, 20);
+        cache.insert(3, 30);
+        // Key 1 was the oldest and should have been evicted.
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get(2), Some(20));
+        assert_eq!(cache.get(3), Some(30));
+
+        // Shrinking capacity drops the oldest remaining entry.
+        cache.set_capacity(1);
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(3), Some(30));
+    }
+
+    #[tokio::test]
+    async fn test_glm_encode() {
+        let encoding = glm_encode("Hello, world!").await.unwrap();
+        assert_eq!(encoding.len(), 4);
+        assert_eq!(encoding.ids.len(), encoding.tokens.len());
+        assert_eq!(encoding.ids.len(), encoding.offsets.len());
+        // The count wrapper agrees with the encoding length.
+        assert_eq!(glm("Hello, world!").await.unwrap(), encoding.len());
+    }
+
+    #[tokio::test]
+    async fn test_glm_chat_encode_matches_count() {
+        let messages = vec![ChatMessage::user("Hello, world!".to_owned())];
+        let encoding = glm_chat_encode(messages.clone(), vec![], true).await.unwrap();
+        let count = glm_chat(messages, vec![], true).await.unwrap();
+        // No images, so text length, total, and the reported count all agree.
+        assert_eq!(encoding.image_tokens, 0);
+        assert_eq!(encoding.len(), count);
+        assert_eq!(encoding.total_len(), count);
+        assert!(!encoding.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_glm_chat_encode_total_len_includes_images() {
+        let messages = vec![ChatMessage::user_parts(vec![
+            ContentPart::text("Describe this.".to_owned()),
+            ContentPart::image("https://example.com/cat.png".to_owned()),
+        ])];
+        let encoding = glm_chat_encode(messages.clone(), vec![], true).await.unwrap();
+        let count = glm_chat(messages, vec![], true).await.unwrap();
+        // The image contribution lands in image_tokens, and total_len — not the
+        // bare text length — is what reconciles with glm_chat.
+        assert_eq!(encoding.image_tokens, GLM_IMAGE_TOKENS);
+        assert_eq!(encoding.total_len(), count);
+        assert_eq!(encoding.len() + encoding.image_tokens, count);
+    }
+
+    #[tokio::test]
+    async fn test_glm_chat_image_tokens() {
+        // Same text part in both cases; the only difference is the added image
+        // part. The image contributes at least the fixed per-image tile count;
+        // GLM-V templates also render image placeholder markers
+        // (`<|begin_of_image|>` …) around the part, so the rendered text can add
+        // a few tokens on top. Assert the fixed contribution is a lower bound
+        // rather than an exact delta.
+        let text_part = || ContentPart::text("Describe this.".to_owned());
+
+        let without_image =
+            glm_chat(vec![ChatMessage::user_parts(vec![text_part()])], vec![], true)
+                .await
+                .unwrap();
+
+        let with_image = glm_chat(
+            vec![ChatMessage::user_parts(vec![
+                text_part(),
+                ContentPart::image("https://example.com/cat.png".to_owned()),
+            ])],
+            vec![],
+            true,
+        )
+        .await
+        .unwrap();
+
+        assert!(with_image >= without_image + GLM_IMAGE_TOKENS);
+    }
+
+    #[tokio::test]
+    async fn test_glm_chat_with_tools() {
+        let tools = vec![serde_json::json!({
+            "type": "function",
+            "function": {
+                "name": "get_weather",
+                "description": "Get the weather for a city",
+                "parameters": {
+                    "type": "object",
+                    "properties": { "city": { "type": "string" } },
+                    "required": ["city"],
+                },
+            },
+        })];
+        let messages = vec![ChatMessage::user("What's the weather in Paris?".to_owned())];
+
+        let without_tools = glm_chat(messages.clone(), vec![], true).await.unwrap();
+        let with_tools = glm_chat(messages, tools, true).await.unwrap();
+        // The serialized tool schema should add to the token budget.
+        assert!(with_tools > without_tools);
+    }
+
+    #[tokio::test]
+    async fn test_glm_chat_with_tool_call_turn() {
+        let messages = vec![
+            ChatMessage::user("What's the weather in Paris?".to_owned()),
+            ChatMessage::assistant(String::new()).with_tool_calls(vec![ToolCall {
+                name: "get_weather".to_owned(),
+                arguments: serde_json::json!({ "city": "Paris" }),
+            }]),
+            ChatMessage::tool("{\"temp_c\": 18}".to_owned())
+                .with_tool_call_id("call_0".to_owned()),
+        ];
+        let result = glm_chat(messages, vec![], true).await.unwrap();
+        assert!(result > 0);
+    }
+
+    #[test]
+    fn test_raise_exception_maps_to_template_error() {
+        // A template that enforces alternating user/assistant roles and calls
+        // `raise_exception` when the sequence is violated.
+        const GUARD: &str = r#"
+            {%- for message in messages -%}
+                {%- set expected = "user" if loop.index0 % 2 == 0 else "assistant" -%}
+                {%- if message.role != expected -%}
+                    {{- raise_exception("roles must alternate user/assistant") -}}
+                {%- endif -%}
+            {%- endfor -%}
+        "#;
+
+        let mut env = Environment::new();
+        env.add_function("raise_exception", raise_exception);
+        env.add_template("guard", GUARD).unwrap();
+        let tmpl = env.get_template("guard").unwrap();
+
+        // Alternating roles render cleanly.
+        let ok = tmpl.render(context! {
+            messages => vec![
+                ChatMessage::user("hi".to_owned()),
+                ChatMessage::assistant("hello".to_owned()),
+            ],
+        });
+        assert!(ok.is_ok());
+
+        // Two user turns in a row trip the guard, surfacing as Error::Template.
+        let err = tmpl
+            .render(context! {
+                messages => vec![
+                    ChatMessage::user("hi".to_owned()),
+                    ChatMessage::user("again".to_owned()),
+                ],
+            })
+            .map_err(Error::from)
+            .unwrap_err();
+        assert!(matches!(err, Error::Template(_)));
+        assert!(err.to_string().contains("roles must alternate"));
+    }
 }